@@ -0,0 +1,172 @@
+use std::time::Instant;
+
+use crate::{
+    event::{Event, StartCause},
+    event_loop::ControlFlow,
+};
+
+use crate::platform_impl::platform::event_loop::{self, EventHandler, Never};
+
+// the state captured when the handler settles on `Wait`/`WaitUntil`, used by
+// `handle_wakeup_transition` to figure out what `StartCause` the next wakeup deserves
+struct Wait {
+    start: Instant,
+    requested_resume: Option<Instant>,
+}
+
+struct AppStateImpl {
+    handler: Box<dyn EventHandler>,
+    control_flow: ControlFlow,
+    wait: Option<Wait>,
+}
+
+// guaranteed to only be touched from the main thread: every entry point below is either called
+// directly from `EventLoop` (already main-thread-only) or from the main `CFRunLoop`'s observers
+static mut APP_STATE: Option<AppStateImpl> = None;
+
+// installed for the duration of a single `EventLoop::pump_events` call; takes priority over the
+// permanent handler in `APP_STATE` while it's set
+static mut PUMPED_HANDLER: Option<Box<dyn EventHandler>> = None;
+
+pub struct AppState;
+
+impl AppState {
+    // installs the handler that stays active for the life of the process; called by `run`
+    // right before it hands control to `UIApplicationMain`
+    pub unsafe fn will_launch(handler: Box<dyn EventHandler>) {
+        assert!(
+            APP_STATE.is_none(),
+            "an `EventLoop` has already launched on iOS"
+        );
+        APP_STATE = Some(AppStateImpl {
+            handler,
+            control_flow: ControlFlow::Poll,
+            wait: None,
+        });
+        Self::with_active_handler(|handler, control_flow| {
+            handler.handle_nonuser_event(Event::NewEvents(StartCause::Init), control_flow);
+        });
+        Self::commit_control_flow();
+    }
+
+    // called from the `kCFRunLoopAfterWaiting` observer (including when the wakeup timer woke
+    // the loop) and from `handle_events_cleared` when it notices an already-elapsed deadline
+    pub unsafe fn handle_wakeup_transition() {
+        let cause = match APP_STATE.as_mut().and_then(|state| state.wait.take()) {
+            Some(Wait {
+                start,
+                requested_resume: Some(requested_resume),
+            }) if Instant::now() >= requested_resume => StartCause::ResumeTimeReached {
+                start,
+                requested_resume,
+            },
+            Some(Wait {
+                start,
+                requested_resume,
+            }) => StartCause::WaitCancelled {
+                start,
+                requested_resume,
+            },
+            None => StartCause::Poll,
+        };
+
+        Self::with_active_handler(|handler, control_flow| {
+            handler.handle_nonuser_event(Event::NewEvents(cause), control_flow);
+            handler.handle_user_events(control_flow);
+        });
+        Self::commit_control_flow();
+    }
+
+    // called from the `kCFRunLoopBeforeWaiting` observer
+    pub unsafe fn handle_events_cleared() {
+        Self::with_active_handler(|handler, control_flow| {
+            handler.handle_nonuser_event(Event::MainEventsCleared, control_flow);
+        });
+        Self::commit_control_flow();
+
+        // `Poll` isn't a wait at all, so leave `wait` as `None`; `handle_wakeup_transition`
+        // reads that back as `StartCause::Poll` rather than mistaking it for a cancelled wait
+        let control_flow = Self::control_flow();
+        let wait = match control_flow {
+            ControlFlow::Poll => None,
+            ControlFlow::WaitUntil(deadline) => Some(Wait {
+                start: Instant::now(),
+                requested_resume: Some(deadline),
+            }),
+            _ => Some(Wait {
+                start: Instant::now(),
+                requested_resume: None,
+            }),
+        };
+        if let Some(state) = APP_STATE.as_mut() {
+            state.wait = wait;
+        }
+
+        // the requested deadline may already be behind us by the time we get here (e.g. a very
+        // short `WaitUntil` set from inside the `MainEventsCleared` we just delivered); don't
+        // wait for the timer to fire in that case, dispatch the resume right away
+        if let ControlFlow::WaitUntil(deadline) = control_flow {
+            if Instant::now() >= deadline {
+                Self::handle_wakeup_transition();
+            }
+        }
+    }
+
+    // installs the handler for a single `EventLoop::pump_events` call; seeds a placeholder
+    // permanent handler the first time it's called, since pumping never goes through
+    // `will_launch`/`UIApplicationMain`
+    pub unsafe fn set_pumped_event_handler(handler: Box<dyn EventHandler>) {
+        if APP_STATE.is_none() {
+            APP_STATE = Some(AppStateImpl {
+                handler: Box::new(NoopHandler),
+                control_flow: ControlFlow::Poll,
+                wait: None,
+            });
+        }
+        PUMPED_HANDLER = Some(handler);
+    }
+
+    // uninstalls the handler set by `set_pumped_event_handler`; called once
+    // `CFRunLoopRunInMode` returns control to `pump_events`
+    pub unsafe fn clear_pumped_event_handler() {
+        PUMPED_HANDLER = None;
+    }
+
+    pub unsafe fn control_flow() -> ControlFlow {
+        APP_STATE
+            .as_ref()
+            .map_or(ControlFlow::Poll, |state| state.control_flow)
+    }
+
+    // runs `f` against the active handler (the pumped one, if one is installed, else the
+    // permanent one) with the current `ControlFlow`, then stores back whatever `f` left it as
+    unsafe fn with_active_handler(f: impl FnOnce(&mut dyn EventHandler, &mut ControlFlow)) {
+        let state = APP_STATE
+            .as_mut()
+            .expect("`AppState` wasn't launched before use");
+        let mut control_flow = state.control_flow;
+        let handler = match PUMPED_HANDLER.as_mut() {
+            Some(handler) => handler.as_mut(),
+            None => state.handler.as_mut(),
+        };
+        f(handler, &mut control_flow);
+        APP_STATE.as_mut().unwrap().control_flow = control_flow;
+    }
+
+    // pushes the now-current `ControlFlow` out to the `CFRunLoopTimer` that wakes the main run
+    // loop for `WaitUntil`, so a deadline the handler just requested actually gets armed
+    unsafe fn commit_control_flow() {
+        event_loop::reschedule_wakeup_timer(Self::control_flow());
+    }
+}
+
+// placeholder permanent handler for `EventLoop::pump_events`, which has no `will_launch`-style
+// call of its own to install one; `PUMPED_HANDLER` takes priority over this during a pump, and
+// events the main run loop's observers deliver between `pump_events` calls are dropped here
+#[derive(Debug)]
+struct NoopHandler;
+
+impl EventHandler for NoopHandler {
+    fn handle_nonuser_event(&mut self, _event: Event<Never>, _control_flow: &mut ControlFlow) {}
+    fn handle_user_events(&mut self, _control_flow: &mut ControlFlow) {}
+}
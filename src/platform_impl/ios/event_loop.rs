@@ -4,7 +4,12 @@ use std::{
     fmt::{self, Debug},
     marker::PhantomData,
     mem, ptr,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
@@ -19,18 +24,26 @@ use crate::platform_impl::platform::{
     app_state::AppState,
     ffi::{
         id, kCFRunLoopAfterWaiting, kCFRunLoopBeforeWaiting, kCFRunLoopCommonModes,
-        kCFRunLoopDefaultMode, kCFRunLoopEntry, kCFRunLoopExit, nil, CFIndex, CFRelease,
-        CFRunLoopActivity, CFRunLoopAddObserver, CFRunLoopAddSource, CFRunLoopGetMain,
-        CFRunLoopObserverCreate, CFRunLoopObserverRef, CFRunLoopSourceContext,
-        CFRunLoopSourceCreate, CFRunLoopSourceInvalidate, CFRunLoopSourceRef,
-        CFRunLoopSourceSignal, CFRunLoopWakeUp, NSString, UIApplicationMain, UIUserInterfaceIdiom,
+        kCFRunLoopDefaultMode, kCFRunLoopEntry, kCFRunLoopExit, nil, CFAbsoluteTimeGetCurrent,
+        CFIndex, CFRelease, CFRunLoopActivity, CFRunLoopAddObserver, CFRunLoopAddSource,
+        CFRunLoopAddTimer, CFRunLoopGetMain, CFRunLoopObserverCreate, CFRunLoopObserverRef,
+        CFRunLoopRunInMode, CFRunLoopSourceContext, CFRunLoopSourceCreate,
+        CFRunLoopSourceInvalidate, CFRunLoopSourceRef, CFRunLoopSourceSignal,
+        CFRunLoopTimerContext, CFRunLoopTimerCreate, CFRunLoopTimerRef,
+        CFRunLoopTimerSetNextFireDate, CFRunLoopWakeUp, CFTimeInterval, NSString,
+        UIApplicationMain, UIUserInterfaceIdiom,
     },
     monitor, view, MonitorHandle,
 };
 
+// the `CFRunLoopTimer` used to wake the main run loop for `ControlFlow::WaitUntil`; dormant
+// (fire date pushed out to `CFTimeInterval::MAX`) while the handler requested `Wait`.
+static mut WAKEUP_TIMER: CFRunLoopTimerRef = ptr::null_mut();
+
 pub struct EventLoopWindowTarget<T: 'static> {
     receiver: Receiver<T>,
     sender_to_clone: Sender<T>,
+    waker: Arc<EventLoopWaker>,
 }
 
 pub struct EventLoop<T: 'static> {
@@ -52,6 +65,7 @@ impl<T: 'static> EventLoop<T> {
         }
 
         let (sender_to_clone, receiver) = mpsc::channel();
+        let waker = Arc::new(EventLoopWaker::new());
 
         // this line sets up the main run loop before `UIApplicationMain`
         setup_control_flow_observers();
@@ -61,6 +75,7 @@ impl<T: 'static> EventLoop<T> {
                 p: EventLoopWindowTarget {
                     receiver,
                     sender_to_clone,
+                    waker,
                 },
                 _marker: PhantomData,
             },
@@ -80,9 +95,13 @@ impl<T: 'static> EventLoop<T> {
                  `EventLoop` cannot be `run` after a call to `UIApplicationMain` on iOS\n\
                  Note: `EventLoop::run` calls `UIApplicationMain` on iOS"
             );
+            // SAFETY: `run` never returns, so `self` (and the `window_target` it owns) lives
+            // for the remainder of the process, making this reference effectively `'static`.
+            let event_loop: &'static RootEventLoopWindowTarget<T> =
+                mem::transmute(&self.window_target);
             AppState::will_launch(Box::new(EventLoopHandler {
                 f: event_handler,
-                event_loop: self.window_target,
+                event_loop,
             }));
 
             UIApplicationMain(
@@ -95,8 +114,43 @@ impl<T: 'static> EventLoop<T> {
         }
     }
 
+    /// Pumps the event loop a single time without calling `UIApplicationMain`, for embedding
+    /// winit inside a host `CFRunLoop` that winit doesn't own (e.g. an app-extension or
+    /// audio-plugin host, the scenario that motivated nih-plug's native macOS event loop).
+    ///
+    /// Unlike `run`, this doesn't take ownership of the `EventLoop` and may be called
+    /// repeatedly; each call installs `event_handler` (only for the duration of the call),
+    /// drives the main `CFRunLoop` for at most `timeout`, then returns the `ControlFlow` the
+    /// handler settled on so the host can decide when to call back in.
+    pub fn pump_events<F>(&mut self, timeout: Option<Duration>, mut event_handler: F) -> ControlFlow
+    where
+        F: FnMut(Event<T>, &RootEventLoopWindowTarget<T>, &mut ControlFlow),
+    {
+        unsafe {
+            let handler = EventLoopHandler {
+                f: &mut event_handler,
+                event_loop: &self.window_target,
+            };
+            // SAFETY: `AppState` only holds onto `handler` for the duration of the
+            // `CFRunLoopRunInMode` call below, which returns before `pump_events` does, so
+            // erasing its lifetime here never lets it outlive `event_handler` or `self`.
+            let handler: Box<dyn EventHandler> =
+                mem::transmute::<Box<dyn EventHandler + '_>, _>(Box::new(handler));
+            AppState::set_pumped_event_handler(handler);
+
+            let timeout = timeout.map_or(CFTimeInterval::MAX, |duration| duration.as_secs_f64());
+            CFRunLoopRunInMode(kCFRunLoopDefaultMode, timeout, 1);
+
+            AppState::clear_pumped_event_handler();
+            AppState::control_flow()
+        }
+    }
+
     pub fn create_proxy(&self) -> EventLoopProxy<T> {
-        EventLoopProxy::new(self.window_target.p.sender_to_clone.clone())
+        EventLoopProxy::new(
+            self.window_target.p.sender_to_clone.clone(),
+            self.window_target.p.waker.clone(),
+        )
     }
 
     pub fn available_monitors(&self) -> VecDeque<MonitorHandle> {
@@ -124,18 +178,43 @@ impl<T: 'static> EventLoop<T> {
 
 pub struct EventLoopProxy<T> {
     sender: Sender<T>,
-    source: CFRunLoopSourceRef,
+    waker: Arc<EventLoopWaker>,
 }
 
 unsafe impl<T: Send> Send for EventLoopProxy<T> {}
 
 impl<T> Clone for EventLoopProxy<T> {
     fn clone(&self) -> EventLoopProxy<T> {
-        EventLoopProxy::new(self.sender.clone())
+        EventLoopProxy::new(self.sender.clone(), self.waker.clone())
+    }
+}
+
+impl<T> EventLoopProxy<T> {
+    fn new(sender: Sender<T>, waker: Arc<EventLoopWaker>) -> EventLoopProxy<T> {
+        EventLoopProxy { sender, waker }
+    }
+
+    pub fn send_event(&self, event: T) -> Result<(), EventLoopClosed> {
+        self.sender.send(event).map_err(|_| EventLoopClosed)?;
+        // let the main thread know there's a new event
+        self.waker.wake();
+        Ok(())
     }
 }
 
-impl<T> Drop for EventLoopProxy<T> {
+// a single `CFRunLoopSource` shared by an `EventLoop` and every `EventLoopProxy` cloned from it,
+// with an idempotent signal: bursty senders only pay for one `CFRunLoopSourceSignal`/
+// `CFRunLoopWakeUp` per drain instead of one per `send_event` call. Borrows the coalescing
+// approach from the nativeshell run-loop wrapper.
+struct EventLoopWaker {
+    source: CFRunLoopSourceRef,
+    wakeup_pending: AtomicBool,
+}
+
+unsafe impl Send for EventLoopWaker {}
+unsafe impl Sync for EventLoopWaker {}
+
+impl Drop for EventLoopWaker {
     fn drop(&mut self) {
         unsafe {
             CFRunLoopSourceInvalidate(self.source);
@@ -144,8 +223,8 @@ impl<T> Drop for EventLoopProxy<T> {
     }
 }
 
-impl<T> EventLoopProxy<T> {
-    fn new(sender: Sender<T>) -> EventLoopProxy<T> {
+impl EventLoopWaker {
+    fn new() -> EventLoopWaker {
         unsafe {
             // just wake up the eventloop
             extern "C" fn event_loop_proxy_handler(_: *mut c_void) {}
@@ -161,19 +240,28 @@ impl<T> EventLoopProxy<T> {
             CFRunLoopAddSource(rl, source, kCFRunLoopCommonModes);
             CFRunLoopWakeUp(rl);
 
-            EventLoopProxy { sender, source }
+            EventLoopWaker {
+                source,
+                wakeup_pending: AtomicBool::new(false),
+            }
         }
     }
 
-    pub fn send_event(&self, event: T) -> Result<(), EventLoopClosed> {
-        self.sender.send(event).map_err(|_| EventLoopClosed)?;
+    fn wake(&self) {
+        // only signal the run loop if no wakeup is already outstanding; `handle_user_events`
+        // calls `clear_pending` right before it drains the queue, so a send that finds the flag
+        // still set has its event queued ahead of that drain and is picked up by it
+        if self.wakeup_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
         unsafe {
-            // let the main thread know there's a new event
             CFRunLoopSourceSignal(self.source);
-            let rl = CFRunLoopGetMain();
-            CFRunLoopWakeUp(rl);
+            CFRunLoopWakeUp(CFRunLoopGetMain());
         }
-        Ok(())
+    }
+
+    fn clear_pending(&self) {
+        self.wakeup_pending.store(false, Ordering::Release);
     }
 }
 
@@ -189,7 +277,12 @@ fn setup_control_flow_observers() {
                 #[allow(non_upper_case_globals)]
                 match activity {
                     kCFRunLoopAfterWaiting => AppState::handle_wakeup_transition(),
-                    kCFRunLoopEntry => unimplemented!(), // not expected to ever happen
+                    // now registered on common modes rather than just the default mode, this
+                    // observer is copied into every mode the run loop enters (e.g.
+                    // `UITrackingRunLoopMode` during a scroll/gesture), so `Entry` fires once
+                    // per mode push rather than just once at process startup; nothing to do
+                    // here, `Init` is dispatched from `AppState::will_launch` instead
+                    kCFRunLoopEntry => {}
                     _ => unreachable!(),
                 }
             }
@@ -206,7 +299,9 @@ fn setup_control_flow_observers() {
                 #[allow(non_upper_case_globals)]
                 match activity {
                     kCFRunLoopBeforeWaiting => AppState::handle_events_cleared(),
-                    kCFRunLoopExit => unimplemented!(), // not expected to ever happen
+                    // same reasoning as `kCFRunLoopEntry` above: common-mode registration means
+                    // this fires once per mode pop, not just once at process teardown
+                    kCFRunLoopExit => {}
                     _ => unreachable!(),
                 }
             }
@@ -221,7 +316,10 @@ fn setup_control_flow_observers() {
             control_flow_begin_handler,
             ptr::null_mut(),
         );
-        CFRunLoopAddObserver(main_loop, begin_observer, kCFRunLoopDefaultMode);
+        // registered against common modes (rather than just the default mode) so winit keeps
+        // pumping its event callback while UIKit is in a tracking mode, e.g. during an
+        // interactive scroll or gesture (mirrors Chromium's CFRunLoop message pump)
+        CFRunLoopAddObserver(main_loop, begin_observer, kCFRunLoopCommonModes);
         let end_observer = CFRunLoopObserverCreate(
             ptr::null_mut(),
             kCFRunLoopExit | kCFRunLoopBeforeWaiting,
@@ -230,10 +328,48 @@ fn setup_control_flow_observers() {
             control_flow_end_handler,
             ptr::null_mut(),
         );
-        CFRunLoopAddObserver(main_loop, end_observer, kCFRunLoopDefaultMode);
+        CFRunLoopAddObserver(main_loop, end_observer, kCFRunLoopCommonModes);
+
+        // just wake up the eventloop; the `kCFRunLoopAfterWaiting` observer's
+        // `AppState::handle_wakeup_transition` turns the wakeup into a
+        // `StartCause::ResumeTimeReached` once the requested deadline has passed
+        extern "C" fn wakeup_timer_handler(_: CFRunLoopTimerRef, _: *mut c_void) {}
+
+        // we want all the members of context to be zero/null, except one
+        let mut context: CFRunLoopTimerContext = mem::zeroed();
+        context.info = ptr::null_mut();
+        WAKEUP_TIMER = CFRunLoopTimerCreate(
+            ptr::null_mut(),
+            CFTimeInterval::MAX, // dormant until a `WaitUntil` schedules it
+            // a non-repeating timer invalidates itself once it fires and can't be rescheduled,
+            // so repeat with a negligible interval; we reschedule it explicitly
+            0.000_000_1,
+            0,
+            0,
+            wakeup_timer_handler,
+            &mut context,
+        );
+        CFRunLoopAddTimer(main_loop, WAKEUP_TIMER, kCFRunLoopCommonModes);
     }
 }
 
+// called whenever the handler settles on a new `ControlFlow`, so the wakeup timer always
+// reflects the most recently requested deadline
+pub(crate) unsafe fn reschedule_wakeup_timer(control_flow: ControlFlow) {
+    let fire_date = match control_flow {
+        ControlFlow::Poll => CFAbsoluteTimeGetCurrent() - 1.0, // distant past: fire immediately
+        ControlFlow::Wait => CFTimeInterval::MAX,              // distant future: stay dormant
+        ControlFlow::WaitUntil(deadline) => {
+            CFAbsoluteTimeGetCurrent()
+                + deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f64()
+        }
+        ControlFlow::Exit => return,
+    };
+    CFRunLoopTimerSetNextFireDate(WAKEUP_TIMER, fire_date);
+}
+
 #[derive(Debug)]
 pub enum Never {}
 
@@ -242,12 +378,16 @@ pub trait EventHandler: Debug {
     fn handle_user_events(&mut self, control_flow: &mut ControlFlow);
 }
 
-struct EventLoopHandler<F, T: 'static> {
+// borrows `event_loop` rather than owning it so `pump_events` can install a handler without
+// taking ownership of `EventLoop::window_target`; sound for `run` because it never returns
+// (`self`, and the `window_target` it owns, lives for the rest of the process), and for
+// `pump_events` because `AppState` only holds the handler for the duration of that one call
+struct EventLoopHandler<'a, F, T: 'static> {
     f: F,
-    event_loop: RootEventLoopWindowTarget<T>,
+    event_loop: &'a RootEventLoopWindowTarget<T>,
 }
 
-impl<F, T: 'static> Debug for EventLoopHandler<F, T> {
+impl<'a, F, T: 'static> Debug for EventLoopHandler<'a, F, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("EventLoopHandler")
             .field("event_loop", &self.event_loop)
@@ -255,22 +395,27 @@ impl<F, T: 'static> Debug for EventLoopHandler<F, T> {
     }
 }
 
-impl<F, T> EventHandler for EventLoopHandler<F, T>
+impl<'a, F, T> EventHandler for EventLoopHandler<'a, F, T>
 where
-    F: 'static + FnMut(Event<T>, &RootEventLoopWindowTarget<T>, &mut ControlFlow),
+    F: FnMut(Event<T>, &RootEventLoopWindowTarget<T>, &mut ControlFlow),
     T: 'static,
 {
     fn handle_nonuser_event(&mut self, event: Event<Never>, control_flow: &mut ControlFlow) {
         (self.f)(
             event.map_nonuser_event().unwrap(),
-            &self.event_loop,
+            self.event_loop,
             control_flow,
         );
     }
 
     fn handle_user_events(&mut self, control_flow: &mut ControlFlow) {
+        // clear the flag *before* draining, not after: a `send_event` that arrives while
+        // `try_iter` below is still running would see `wakeup_pending` already cleared and
+        // re-signal the source, so its event gets picked up by a later wakeup instead of
+        // stalling undelivered until some unrelated wakeup happens to drain the queue for it
+        self.event_loop.p.waker.clear_pending();
         for event in self.event_loop.p.receiver.try_iter() {
-            (self.f)(Event::UserEvent(event), &self.event_loop, control_flow);
+            (self.f)(Event::UserEvent(event), self.event_loop, control_flow);
         }
     }
 }